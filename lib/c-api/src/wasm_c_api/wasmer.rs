@@ -1,10 +1,27 @@
 //! Non-standard Wasmer-specific extensions to the Wasm C API.
 
+use super::externals::wasm_externkind_t;
+use super::instance::wasm_instance_t;
 use super::module::wasm_module_t;
-use super::types::wasm_name_t;
+use super::store::wasm_store_t;
+use super::types::{wasm_byte_vec_t, wasm_name_t};
+use crate::error::update_last_error;
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::c_char;
+use std::path::PathBuf;
 use std::ptr;
+use std::slice;
 use std::str;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use wasmer::{ExternType, Module};
+use wasmer_cache::{Cache, FileSystemCache, Hash};
+use wasmer_compiler::{
+    CompilerConfig, FunctionMiddleware, MiddlewareError, MiddlewareReaderState, ModuleMiddleware,
+};
+use wasmer_middlewares::metering::{self, MeteringPoints};
+use wasmer_types::{FunctionIndex, FunctionType, LocalFunctionIndex, ModuleInfo, SignatureIndex};
+use wasmparser::Operator;
 
 /// Non-standard Wasmer-specific API to get the module's name,
 /// otherwise `out->size` is set to `0` and `out->data` to `NULL`.
@@ -156,3 +173,1095 @@ pub unsafe extern "C" fn wasm_module_set_name(
         None => false,
     }
 }
+
+/// Non-standard Wasmer-specific API to serialize a compiled module
+/// into a binary blob that can be persisted by the embedder and
+/// reloaded later with [`wasm_module_deserialize`] without going
+/// through compilation again.
+///
+/// The blob embeds a fingerprint of the engine, compiler and target
+/// it was produced with, so that [`wasm_module_deserialize`] can
+/// detect and reject a blob produced by an incompatible backend
+/// instead of handing back an unsafe module.
+///
+/// This function returns `true` on success, `false` otherwise. You
+/// can use [`wasmer_last_error_length`] and
+/// [`wasmer_last_error_message`] to get the error message.
+///
+/// # Example
+///
+/// ```rust
+/// # use inline_c::assert_c;
+/// # fn main() {
+/// #    (assert_c! {
+/// # #include "tests/wasmer_wasm.h"
+/// #
+/// int main() {
+///     wasm_engine_t* engine = wasm_engine_new();
+///     wasm_store_t* store = wasm_store_new(engine);
+///
+///     wasm_byte_vec_t wat;
+///     wasmer_byte_vec_new_from_string(&wat, "(module)");
+///     wasm_byte_vec_t wasm;
+///     wat2wasm(&wat, &wasm);
+///
+///     wasm_module_t* module = wasm_module_new(store, &wasm);
+///
+///     wasm_byte_vec_t serialized;
+///     bool serialized_ok = wasm_module_serialize(module, &serialized);
+///     assert(serialized_ok);
+///
+///     wasm_module_t* deserialized = NULL;
+///     bool deserialized_ok = wasm_module_deserialize(store, &serialized, &deserialized);
+///     assert(deserialized_ok);
+///     assert(deserialized != NULL);
+///
+///     wasm_module_delete(deserialized);
+///     wasm_byte_vec_delete(&serialized);
+///     wasm_module_delete(module);
+///     wasm_byte_vec_delete(&wasm);
+///     wasm_byte_vec_delete(&wat);
+///     wasm_store_delete(store);
+///     wasm_engine_delete(engine);
+///
+///     return 0;
+/// }
+/// #    })
+/// #    .success();
+/// # }
+/// ```
+#[no_mangle]
+pub unsafe extern "C" fn wasm_module_serialize(
+    module: &wasm_module_t,
+    // own
+    out: &mut wasm_byte_vec_t,
+) -> bool {
+    match module.inner.serialize() {
+        Ok(bytes) => {
+            *out = bytes.into_owned().into();
+
+            true
+        }
+        Err(e) => {
+            update_last_error(e);
+
+            false
+        }
+    }
+}
+
+/// Non-standard Wasmer-specific API to deserialize a module
+/// previously produced by [`wasm_module_serialize`].
+///
+/// This is marked `unsafe` at the Rust level (though not in the C
+/// signature) because the blob is assumed to have been produced by
+/// a trusted call to `wasm_module_serialize`; deserializing
+/// attacker-controlled bytes is unsound. If the blob was produced
+/// by an incompatible engine, compiler, or target, this function
+/// fails and returns `false`, leaving `out` untouched, rather than
+/// producing a broken module;
+/// use [`wasmer_last_error_length`] and [`wasmer_last_error_message`]
+/// to retrieve the error.
+///
+/// Returns `true` and sets `out` on success, `false` otherwise.
+///
+/// # Example
+///
+/// ```rust
+/// # use inline_c::assert_c;
+/// # fn main() {
+/// #    (assert_c! {
+/// # #include "tests/wasmer_wasm.h"
+/// #
+/// int main() {
+///     wasm_engine_t* engine = wasm_engine_new();
+///     wasm_store_t* store = wasm_store_new(engine);
+///
+///     // Deserializing a blob that never came from
+///     // `wasm_module_serialize` must fail cleanly instead of
+///     // producing an unsafe module.
+///     wasm_byte_vec_t garbage;
+///     wasmer_byte_vec_new_from_string(&garbage, "not a serialized module");
+///
+///     wasm_module_t* module = NULL;
+///     bool ok = wasm_module_deserialize(store, &garbage, &module);
+///     assert(!ok);
+///     assert(module == NULL);
+///
+///     wasm_byte_vec_delete(&garbage);
+///     wasm_store_delete(store);
+///     wasm_engine_delete(engine);
+///
+///     return 0;
+/// }
+/// #    })
+/// #    .success();
+/// # }
+/// ```
+#[no_mangle]
+pub unsafe extern "C" fn wasm_module_deserialize(
+    store: &wasm_store_t,
+    bytes: &wasm_byte_vec_t,
+    // own
+    out: &mut *mut wasm_module_t,
+) -> bool {
+    let bytes = slice::from_raw_parts(bytes.data, bytes.size);
+
+    match Module::deserialize(&store.inner, bytes) {
+        Ok(module) => {
+            *out = Box::into_raw(Box::new(wasm_module_t {
+                inner: Arc::new(module),
+            }));
+
+            true
+        }
+        Err(e) => {
+            update_last_error(e);
+
+            false
+        }
+    }
+}
+
+/// Non-standard Wasmer-specific API: an on-disk cache of compiled
+/// modules, keyed by a hash of the Wasm bytes combined with the
+/// engine/compiler/target identity (the same key `wasmer-cache`
+/// uses in Rust). Looking a module up with a mismatched backend
+/// misses cleanly instead of returning a corrupt module, because
+/// deserialization (see [`wasm_module_deserialize`]) validates the
+/// fingerprint embedded by [`wasm_module_serialize`].
+#[allow(non_camel_case_types)]
+pub struct wasmer_module_cache_t {
+    inner: FileSystemCache,
+}
+
+/// Names one compiler backend Cargo feature compiled into this
+/// library: `llvm` if that feature is enabled, else `singlepass` if
+/// enabled, else `cranelift`. It does not list the other enabled
+/// backends, nor name the backend a given engine was built with by
+/// [`wasm_engine_new_with_config`]. Unlike `EngineId` (a fresh counter handed out
+/// to every `Engine` at construction time, and therefore different
+/// on every run), this is fixed for the lifetime of the binary, so
+/// it's safe to bake into an on-disk cache key that must survive
+/// process restarts.
+fn compiler_features() -> &'static str {
+    if cfg!(feature = "llvm") {
+        "llvm"
+    } else if cfg!(feature = "singlepass") {
+        "singlepass"
+    } else {
+        "cranelift"
+    }
+}
+
+/// Computes the on-disk cache key for `wasm`: a hash of the Wasm
+/// bytes themselves combined with a fingerprint of `store`'s
+/// engine/compiler/target identity, so that two backends caching
+/// the same bytes never collide on the same key. This is the
+/// primary guard against handing back a module compiled by a
+/// different backend; [`wasm_module_deserialize`]'s own fingerprint
+/// check is only a second line of defense. Built only from values
+/// that stay stable across process restarts, so a cache populated
+/// by one run is still a hit in the next.
+fn hash_for(store: &wasmer::Store, wasm: &[u8]) -> Hash {
+    let mut keyed = wasm.to_vec();
+    keyed.extend_from_slice(format!("{:?}", store.engine().target()).as_bytes());
+    keyed.extend_from_slice(compiler_features().as_bytes());
+
+    Hash::generate(&keyed)
+}
+
+/// Creates a new filesystem-backed module cache rooted at `path`.
+/// Returns `NULL` if the path isn't usable as a cache directory.
+#[no_mangle]
+pub unsafe extern "C" fn wasmer_fs_cache_new(path: *const c_char) -> *mut wasmer_module_cache_t {
+    if path.is_null() {
+        return ptr::null_mut();
+    }
+
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return ptr::null_mut(),
+    };
+
+    match FileSystemCache::new(path) {
+        Ok(inner) => Box::into_raw(Box::new(wasmer_module_cache_t { inner })),
+        Err(e) => {
+            update_last_error(e);
+
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Deletes a module cache created by [`wasmer_fs_cache_new`].
+#[no_mangle]
+pub unsafe extern "C" fn wasmer_module_cache_delete(_cache: Option<Box<wasmer_module_cache_t>>) {}
+
+/// Stores `module` in `cache`, keyed by a hash of `wasm` and the
+/// current engine/compiler/target identity. Returns `true` on
+/// success.
+#[no_mangle]
+pub unsafe extern "C" fn wasmer_module_cache_store(
+    cache: &mut wasmer_module_cache_t,
+    wasm: &wasm_byte_vec_t,
+    module: &wasm_module_t,
+) -> bool {
+    let wasm = slice::from_raw_parts(wasm.data, wasm.size);
+    let key = hash_for(module.inner.store(), wasm);
+
+    match cache.inner.store(key, &module.inner) {
+        Ok(()) => true,
+        Err(e) => {
+            update_last_error(e);
+
+            false
+        }
+    }
+}
+
+/// Loads a previously-cached module for `wasm` from `cache`. Misses
+/// (key not found, or found but produced by an incompatible
+/// engine/compiler/target) return `false` with `out` left
+/// unchanged, so the caller can fall back to compiling `wasm` from
+/// scratch and calling [`wasmer_module_cache_store`].
+///
+/// # Example
+///
+/// ```rust
+/// # use inline_c::assert_c;
+/// # fn main() {
+/// #    (assert_c! {
+/// # #include "tests/wasmer_wasm.h"
+/// #
+/// int main() {
+///     wasm_engine_t* engine = wasm_engine_new();
+///     wasm_store_t* store = wasm_store_new(engine);
+///
+///     wasm_byte_vec_t wat;
+///     wasmer_byte_vec_new_from_string(&wat, "(module)");
+///     wasm_byte_vec_t wasm;
+///     wat2wasm(&wat, &wasm);
+///
+///     wasm_module_t* module = wasm_module_new(store, &wasm);
+///
+///     wasmer_module_cache_t* cache = wasmer_fs_cache_new("wasmer_module_cache_test");
+///     assert(cache != NULL);
+///
+///     bool stored = wasmer_module_cache_store(cache, &wasm, module);
+///     assert(stored);
+///
+///     // A brand new engine/store, as a later process would construct,
+///     // still hits the cache: the key doesn't depend on the `Engine`
+///     // instance that originally compiled `module`.
+///     wasm_engine_t* engine2 = wasm_engine_new();
+///     wasm_store_t* store2 = wasm_store_new(engine2);
+///
+///     wasm_module_t* cached = NULL;
+///     bool loaded = wasmer_module_cache_load(cache, store2, &wasm, &cached);
+///     assert(loaded);
+///     assert(cached != NULL);
+///
+///     wasm_module_delete(cached);
+///     wasm_store_delete(store2);
+///     wasm_engine_delete(engine2);
+///     wasmer_module_cache_delete(cache);
+///     wasm_module_delete(module);
+///     wasm_byte_vec_delete(&wasm);
+///     wasm_byte_vec_delete(&wat);
+///     wasm_store_delete(store);
+///     wasm_engine_delete(engine);
+///
+///     return 0;
+/// }
+/// #    })
+/// #    .success();
+/// # }
+/// ```
+#[no_mangle]
+pub unsafe extern "C" fn wasmer_module_cache_load(
+    cache: &mut wasmer_module_cache_t,
+    store: &wasm_store_t,
+    wasm: &wasm_byte_vec_t,
+    // own
+    out: &mut *mut wasm_module_t,
+) -> bool {
+    let wasm_bytes = slice::from_raw_parts(wasm.data, wasm.size);
+    let key = hash_for(&store.inner, wasm_bytes);
+
+    match cache.inner.load(&store.inner, key) {
+        Ok(module) => {
+            *out = Box::into_raw(Box::new(wasm_module_t {
+                inner: Arc::new(module),
+            }));
+
+            true
+        }
+        Err(e) => {
+            update_last_error(e);
+
+            false
+        }
+    }
+}
+
+/// Non-standard Wasmer-specific API describing one entry of a
+/// module's two-level import namespace (`module`.`name`) together
+/// with the kind of extern it refers to (function, global, table or
+/// memory).
+#[repr(C)]
+pub struct wasmer_named_extern_t {
+    module: wasm_name_t,
+    name: wasm_name_t,
+    kind: wasm_externkind_t,
+}
+
+/// A vector of [`wasmer_named_extern_t`], owned by the caller and
+/// freed with [`wasmer_named_extern_vec_delete`].
+#[repr(C)]
+pub struct wasmer_named_extern_vec_t {
+    size: usize,
+    data: *mut wasmer_named_extern_t,
+}
+
+fn extern_kind(ty: &ExternType) -> wasm_externkind_t {
+    match ty {
+        ExternType::Function(_) => wasm_externkind_t::WASM_EXTERN_FUNC,
+        ExternType::Global(_) => wasm_externkind_t::WASM_EXTERN_GLOBAL,
+        ExternType::Table(_) => wasm_externkind_t::WASM_EXTERN_TABLE,
+        ExternType::Memory(_) => wasm_externkind_t::WASM_EXTERN_MEMORY,
+    }
+}
+
+/// Non-standard Wasmer-specific API to list a module's imports
+/// together with their two-level name and kind, as opposed to
+/// `wasm_module_name` which only exposes the module's own name.
+#[no_mangle]
+pub unsafe extern "C" fn wasmer_module_imports(
+    module: &wasm_module_t,
+    // own
+    out: &mut wasmer_named_extern_vec_t,
+) {
+    let mut named_externs: Vec<wasmer_named_extern_t> = module
+        .inner
+        .imports()
+        .map(|import| wasmer_named_extern_t {
+            module: import.module().as_bytes().to_vec().into(),
+            name: import.name().as_bytes().to_vec().into(),
+            kind: extern_kind(import.ty()),
+        })
+        .collect();
+
+    named_externs.shrink_to_fit();
+    out.size = named_externs.len();
+    out.data = named_externs.as_mut_ptr();
+    std::mem::forget(named_externs);
+}
+
+/// Non-standard Wasmer-specific API to list a module's exports
+/// together with their name and kind, as opposed to
+/// `wasm_module_name` which only exposes the module's own name.
+#[no_mangle]
+pub unsafe extern "C" fn wasmer_module_exports(
+    module: &wasm_module_t,
+    // own
+    out: &mut wasmer_named_extern_vec_t,
+) {
+    let mut named_externs: Vec<wasmer_named_extern_t> = module
+        .inner
+        .exports()
+        .map(|export| wasmer_named_extern_t {
+            module: Vec::new().into(),
+            name: export.name().as_bytes().to_vec().into(),
+            kind: extern_kind(export.ty()),
+        })
+        .collect();
+
+    named_externs.shrink_to_fit();
+    out.size = named_externs.len();
+    out.data = named_externs.as_mut_ptr();
+    std::mem::forget(named_externs);
+}
+
+/// Frees a vector returned by [`wasmer_module_imports`] or
+/// [`wasmer_module_exports`], including the `module`/`name` byte
+/// buffers owned by each of its elements.
+///
+/// # Example
+///
+/// ```rust
+/// # use inline_c::assert_c;
+/// # fn main() {
+/// #    (assert_c! {
+/// # #include "tests/wasmer_wasm.h"
+/// #
+/// int main() {
+///     wasm_engine_t* engine = wasm_engine_new();
+///     wasm_store_t* store = wasm_store_new(engine);
+///
+///     wasm_byte_vec_t wat;
+///     wasmer_byte_vec_new_from_string(
+///         &wat,
+///         "(module (import \"env\" \"thunk\" (func)) (func (export \"run\")))"
+///     );
+///     wasm_byte_vec_t wasm;
+///     wat2wasm(&wat, &wasm);
+///
+///     wasm_module_t* module = wasm_module_new(store, &wasm);
+///
+///     wasmer_named_extern_vec_t imports;
+///     wasmer_module_imports(module, &imports);
+///     assert(imports.size == 1);
+///     wasmer_named_extern_vec_delete(&imports);
+///
+///     wasmer_named_extern_vec_t exports;
+///     wasmer_module_exports(module, &exports);
+///     assert(exports.size == 1);
+///     wasmer_named_extern_vec_delete(&exports);
+///
+///     wasm_module_delete(module);
+///     wasm_byte_vec_delete(&wasm);
+///     wasm_byte_vec_delete(&wat);
+///     wasm_store_delete(store);
+///     wasm_engine_delete(engine);
+///
+///     return 0;
+/// }
+/// #    })
+/// #    .success();
+/// # }
+/// ```
+#[no_mangle]
+pub unsafe extern "C" fn wasmer_named_extern_vec_delete(vec: &mut wasmer_named_extern_vec_t) {
+    if !vec.data.is_null() {
+        let named_externs = Vec::from_raw_parts(vec.data, vec.size, vec.size);
+
+        for named_extern in named_externs {
+            if !named_extern.module.data.is_null() {
+                let _ = Vec::from_raw_parts(
+                    named_extern.module.data,
+                    named_extern.module.size,
+                    named_extern.module.size,
+                );
+            }
+
+            if !named_extern.name.data.is_null() {
+                let _ = Vec::from_raw_parts(
+                    named_extern.name.data,
+                    named_extern.name.size,
+                    named_extern.name.size,
+                );
+            }
+        }
+
+        vec.data = ptr::null_mut();
+        vec.size = 0;
+    }
+}
+
+/// Non-standard Wasmer-specific API to pick which compiler backend
+/// [`wasm_engine_new_with_config`] should use, mirroring the
+/// `cranelift` / `llvm` / `singlepass` Cargo feature choice Rust
+/// embedders make at compile time.
+#[repr(C)]
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum wasmer_compiler_t {
+    CRANELIFT,
+    LLVM,
+    SINGLEPASS,
+}
+
+/// Non-standard Wasmer-specific API describing how instances built
+/// from a [`wasm_engine_new_with_config`] engine configured with
+/// [`wasmer_config_set_metering`] are metered: a cost (in abstract
+/// "points") charged for every Wasm operator, and a hard ceiling on
+/// how deep the module's operand stack is allowed to grow in any
+/// one function.
+///
+/// This has to be set on the engine *before* any module is
+/// compiled with it — gas accounting and the stack-height check are
+/// instrumented into the Wasm bytecode during compilation (the same
+/// way the `cranelift`/`llvm`/`singlepass` choice in
+/// [`wasmer_config_set_compiler`] only takes effect for modules
+/// compiled after it), so there is no way to retrofit metering onto
+/// an already-compiled [`wasm_module_t`].
+#[repr(C)]
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy)]
+pub struct wasmer_metering_config_t {
+    /// Cost, in points, charged for one Wasm operator of the given
+    /// [`wasmer_operator_kind_t`]. Called once per operator while a
+    /// function is compiled; the per-block total (blocks are
+    /// delimited by control-flow boundaries) is deducted from the
+    /// points counter when execution reaches the control-flow
+    /// operator that ends the block, with a trap if fewer points
+    /// remain than that total. The cost is not charged up front at
+    /// the block's entry.
+    pub cost_function: extern "C" fn(wasmer_operator_kind_t) -> u64,
+    /// The maximum number of points instances of the instrumented
+    /// module are allowed to spend before trapping. Read back and
+    /// written with [`wasmer_instance_get_remaining_points`] and
+    /// [`wasmer_instance_set_remaining_points`].
+    pub initial_limit: u64,
+    /// The maximum height the operand stack may reach in any single
+    /// function. Compilation fails if static analysis finds a
+    /// function that can exceed it.
+    pub max_stack_height: u32,
+}
+
+/// Non-standard Wasmer-specific API categorizing a Wasm operator so
+/// that [`wasmer_metering_config_t::cost_function`] can price, say,
+/// a memory access or a call differently from a cheap constant,
+/// instead of charging every operator the same flat cost.
+#[repr(u32)]
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum wasmer_operator_kind_t {
+    WASMER_OPERATOR_CONST,
+    WASMER_OPERATOR_LOCAL,
+    WASMER_OPERATOR_GLOBAL,
+    WASMER_OPERATOR_MEMORY,
+    WASMER_OPERATOR_CALL,
+    WASMER_OPERATOR_CONTROL,
+    WASMER_OPERATOR_OTHER,
+}
+
+fn operator_kind(operator: &Operator) -> wasmer_operator_kind_t {
+    use wasmer_operator_kind_t::*;
+
+    match operator {
+        Operator::I32Const { .. }
+        | Operator::I64Const { .. }
+        | Operator::F32Const { .. }
+        | Operator::F64Const { .. } => WASMER_OPERATOR_CONST,
+
+        Operator::LocalGet { .. } | Operator::LocalSet { .. } | Operator::LocalTee { .. } => {
+            WASMER_OPERATOR_LOCAL
+        }
+
+        Operator::GlobalGet { .. } | Operator::GlobalSet { .. } => WASMER_OPERATOR_GLOBAL,
+
+        Operator::I32Load { .. }
+        | Operator::I64Load { .. }
+        | Operator::F32Load { .. }
+        | Operator::F64Load { .. }
+        | Operator::I32Load8S { .. }
+        | Operator::I32Load8U { .. }
+        | Operator::I32Load16S { .. }
+        | Operator::I32Load16U { .. }
+        | Operator::I64Load8S { .. }
+        | Operator::I64Load8U { .. }
+        | Operator::I64Load16S { .. }
+        | Operator::I64Load16U { .. }
+        | Operator::I64Load32S { .. }
+        | Operator::I64Load32U { .. }
+        | Operator::I32Store { .. }
+        | Operator::I64Store { .. }
+        | Operator::F32Store { .. }
+        | Operator::F64Store { .. }
+        | Operator::I32Store8 { .. }
+        | Operator::I32Store16 { .. }
+        | Operator::I64Store8 { .. }
+        | Operator::I64Store16 { .. }
+        | Operator::I64Store32 { .. }
+        | Operator::MemorySize { .. }
+        | Operator::MemoryGrow { .. } => WASMER_OPERATOR_MEMORY,
+
+        Operator::Call { .. } | Operator::CallIndirect { .. } => WASMER_OPERATOR_CALL,
+
+        Operator::Block { .. }
+        | Operator::Loop { .. }
+        | Operator::If { .. }
+        | Operator::Else
+        | Operator::End
+        | Operator::Br { .. }
+        | Operator::BrIf { .. }
+        | Operator::BrTable { .. }
+        | Operator::Return => WASMER_OPERATOR_CONTROL,
+
+        _ => WASMER_OPERATOR_OTHER,
+    }
+}
+
+/// Returns how many values `operator` pops off and pushes onto the
+/// operand stack. `call_arity` is consulted for `call`/`call
+/// indirect`, whose pop/push count depends on the callee's
+/// signature; when it isn't known (the function or type index
+/// wasn't found, which shouldn't happen for a well-formed module)
+/// this falls back to assuming a single result is pushed. Operators
+/// this table doesn't otherwise name are conservatively assumed to
+/// push one value without popping any, which only loosens (never
+/// tightens) the computed height bound.
+fn operator_stack_effect(operator: &Operator, call_arity: Option<(u32, u32)>) -> (u32, u32) {
+    use Operator::*;
+
+    match operator {
+        Block { .. } | Loop { .. } | Else | End | Nop | Unreachable | Br { .. } | Return => (0, 0),
+
+        If { .. } | BrIf { .. } | BrTable { .. } => (1, 0),
+
+        Call { .. } | CallIndirect { .. } => call_arity.unwrap_or((0, 1)),
+
+        Drop => (1, 0),
+        Select => (3, 1),
+
+        LocalGet { .. } | GlobalGet { .. } => (0, 1),
+        LocalSet { .. } | GlobalSet { .. } => (1, 0),
+        LocalTee { .. } => (1, 1),
+
+        I32Const { .. } | I64Const { .. } | F32Const { .. } | F64Const { .. } => (0, 1),
+
+        I32Load { .. } | I64Load { .. } | F32Load { .. } | F64Load { .. }
+        | I32Load8S { .. } | I32Load8U { .. } | I32Load16S { .. } | I32Load16U { .. }
+        | I64Load8S { .. } | I64Load8U { .. } | I64Load16S { .. } | I64Load16U { .. }
+        | I64Load32S { .. } | I64Load32U { .. } => (1, 1),
+
+        I32Store { .. } | I64Store { .. } | F32Store { .. } | F64Store { .. }
+        | I32Store8 { .. } | I32Store16 { .. } | I64Store8 { .. } | I64Store16 { .. }
+        | I64Store32 { .. } => (2, 0),
+
+        MemorySize { .. } => (0, 1),
+        MemoryGrow { .. } => (1, 1),
+
+        // Unary numeric operators: one operand in, one result out.
+        I32Eqz | I64Eqz | I32Clz | I32Ctz | I32Popcnt | I64Clz | I64Ctz | I64Popcnt | F32Abs
+        | F32Neg | F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt | F64Abs | F64Neg
+        | F64Ceil | F64Floor | F64Trunc | F64Nearest | F64Sqrt | I32WrapI64 | I32TruncF32S
+        | I32TruncF32U | I32TruncF64S | I32TruncF64U | I64ExtendI32S | I64ExtendI32U
+        | I64TruncF32S | I64TruncF32U | I64TruncF64S | I64TruncF64U | F32ConvertI32S
+        | F32ConvertI32U | F32ConvertI64S | F32ConvertI64U | F32DemoteF64 | F64ConvertI32S
+        | F64ConvertI32U | F64ConvertI64S | F64ConvertI64U | F64PromoteF32
+        | I32ReinterpretF32 | I64ReinterpretF64 | F32ReinterpretI32 | F64ReinterpretI64
+        | I32Extend8S | I32Extend16S | I64Extend8S | I64Extend16S | I64Extend32S => (1, 1),
+
+        // Binary numeric operators: two operands in, one result out.
+        I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU | I32GeS | I32GeU
+        | I64Eq | I64Ne | I64LtS | I64LtU | I64GtS | I64GtU | I64LeS | I64LeU | I64GeS
+        | I64GeU | F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge | F64Eq | F64Ne | F64Lt
+        | F64Gt | F64Le | F64Ge | I32Add | I32Sub | I32Mul | I32DivS | I32DivU | I32RemS
+        | I32RemU | I32And | I32Or | I32Xor | I32Shl | I32ShrS | I32ShrU | I32Rotl | I32Rotr
+        | I64Add | I64Sub | I64Mul | I64DivS | I64DivU | I64RemS | I64RemU | I64And | I64Or
+        | I64Xor | I64Shl | I64ShrS | I64ShrU | I64Rotl | I64Rotr | F32Add | F32Sub | F32Mul
+        | F32Div | F32Min | F32Max | F32Copysign | F64Add | F64Sub | F64Mul | F64Div | F64Min
+        | F64Max | F64Copysign => (2, 1),
+
+        _ => (0, 1),
+    }
+}
+
+/// A [`ModuleMiddleware`] that rejects compilation of any function
+/// whose statically-computed maximum operand-stack height exceeds a
+/// configured limit, so that instances can't be driven to grow
+/// their stack without bound. See [`operator_stack_effect`] for the
+/// per-operator accounting and [`wasm_engine_new_with_config`]'s
+/// registration of this alongside a companion `Metering` middleware
+/// for the gas side of the same config.
+struct StackLimiter {
+    max_height: u32,
+    signatures: Mutex<wasmer_types::PrimaryMap<SignatureIndex, FunctionType>>,
+    function_signatures: Mutex<wasmer_types::PrimaryMap<FunctionIndex, SignatureIndex>>,
+}
+
+impl fmt::Debug for StackLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StackLimiter")
+            .field("max_height", &self.max_height)
+            .finish()
+    }
+}
+
+impl ModuleMiddleware for StackLimiter {
+    fn generate_function_middleware(
+        &self,
+        _local_function_index: LocalFunctionIndex,
+    ) -> Box<dyn FunctionMiddleware> {
+        Box::new(StackLimiterFunction {
+            max_height: self.max_height,
+            height: 0,
+            signatures: self.signatures.lock().unwrap().clone(),
+            function_signatures: self.function_signatures.lock().unwrap().clone(),
+        })
+    }
+
+    fn transform_module_info(&self, module_info: &mut ModuleInfo) {
+        *self.signatures.lock().unwrap() = module_info.signatures.clone();
+        *self.function_signatures.lock().unwrap() = module_info.functions.clone();
+    }
+}
+
+#[derive(Debug)]
+struct StackLimiterFunction {
+    max_height: u32,
+    height: i64,
+    signatures: wasmer_types::PrimaryMap<SignatureIndex, FunctionType>,
+    function_signatures: wasmer_types::PrimaryMap<FunctionIndex, SignatureIndex>,
+}
+
+impl StackLimiterFunction {
+    fn call_arity(&self, operator: &Operator) -> Option<(u32, u32)> {
+        let signature = match operator {
+            Operator::Call { function_index } => {
+                let signature_index = *self
+                    .function_signatures
+                    .get(FunctionIndex::from_u32(*function_index))?;
+
+                self.signatures.get(signature_index)?
+            }
+            Operator::CallIndirect { index, .. } => {
+                self.signatures.get(SignatureIndex::from_u32(*index))?
+            }
+            _ => return None,
+        };
+
+        Some((signature.params().len() as u32, signature.results().len() as u32))
+    }
+}
+
+impl FunctionMiddleware for StackLimiterFunction {
+    fn feed<'a>(
+        &mut self,
+        operator: Operator<'a>,
+        state: &mut MiddlewareReaderState<'a>,
+    ) -> Result<(), MiddlewareError> {
+        let (pop, push) = operator_stack_effect(&operator, self.call_arity(&operator));
+
+        self.height = (self.height - pop as i64).max(0) + push as i64;
+
+        if self.height > self.max_height as i64 {
+            return Err(MiddlewareError::new(
+                "stack-limiter",
+                format!(
+                    "function exceeds the configured maximum operand-stack height of {}",
+                    self.max_height
+                ),
+            ));
+        }
+
+        state.push_operator(operator);
+
+        Ok(())
+    }
+}
+
+/// Non-standard Wasmer-specific API: a configuration object used to
+/// build an engine with [`wasm_engine_new_with_config`]. Created
+/// with [`wasmer_config_new`] and tuned with
+/// [`wasmer_config_set_compiler`] and, optionally,
+/// [`wasmer_config_set_metering`].
+#[allow(non_camel_case_types)]
+pub struct wasmer_config_t {
+    compiler: wasmer_compiler_t,
+    metering: Option<wasmer_metering_config_t>,
+}
+
+/// Creates a new engine configuration, defaulting to the Cranelift
+/// compiler and no metering.
+#[no_mangle]
+pub extern "C" fn wasmer_config_new() -> Box<wasmer_config_t> {
+    Box::new(wasmer_config_t {
+        compiler: wasmer_compiler_t::CRANELIFT,
+        metering: None,
+    })
+}
+
+/// Selects which compiler backend `config` will ask for.
+#[no_mangle]
+pub extern "C" fn wasmer_config_set_compiler(
+    config: &mut wasmer_config_t,
+    compiler: wasmer_compiler_t,
+) {
+    config.compiler = compiler;
+}
+
+/// Non-standard Wasmer-specific API to have engines built from
+/// `config` (via [`wasm_engine_new_with_config`]) instrument every
+/// module they compile with gas metering and a maximum
+/// operand-stack-height check, so instances of those modules can
+/// bound both how much work untrusted Wasm does and how deep its
+/// stack is allowed to grow — the sandboxing guarantee that
+/// blockchain and other untrusted-code hosts need. See
+/// [`wasmer_metering_config_t`] for the knobs, and
+/// [`wasmer_instance_get_remaining_points`] /
+/// [`wasmer_instance_set_remaining_points`] for the runtime
+/// accessors.
+///
+/// # Example
+///
+/// ```rust
+/// # use inline_c::assert_c;
+/// # fn main() {
+/// #    (assert_c! {
+/// # #include "tests/wasmer_wasm.h"
+/// #
+/// uint64_t cost_of(wasmer_operator_kind_t kind) {
+///     return 1;
+/// }
+///
+/// int main() {
+///     wasmer_config_t* config = wasmer_config_new();
+///     wasmer_config_set_compiler(config, CRANELIFT);
+///
+///     // A function body that pushes four `i32`s before folding them
+///     // with `add` needs an operand stack four deep; capping
+///     // `max_stack_height` at 2 must reject it at compile time.
+///     wasmer_metering_config_t metering = {
+///         .cost_function = cost_of,
+///         .initial_limit = 100,
+///         .max_stack_height = 2,
+///     };
+///     wasmer_config_set_metering(config, &metering);
+///
+///     wasm_engine_t* engine = wasm_engine_new_with_config(config);
+///     assert(engine != NULL);
+///     wasm_store_t* store = wasm_store_new(engine);
+///
+///     wasm_byte_vec_t wat;
+///     wasmer_byte_vec_new_from_string(
+///         &wat,
+///         "(module (func (export \"run\") (result i32)"
+///         "  i32.const 1 i32.const 1 i32.const 1 i32.const 1"
+///         "  i32.add i32.add i32.add))"
+///     );
+///     wasm_byte_vec_t wasm;
+///     wat2wasm(&wat, &wasm);
+///
+///     wasm_module_t* module = wasm_module_new(store, &wasm);
+///     assert(module == NULL);
+///
+///     wasm_byte_vec_delete(&wasm);
+///     wasm_byte_vec_delete(&wat);
+///     wasm_store_delete(store);
+///     wasm_engine_delete(engine);
+///
+///     return 0;
+/// }
+/// #    })
+/// #    .success();
+/// # }
+/// ```
+#[no_mangle]
+pub extern "C" fn wasmer_config_set_metering(
+    config: &mut wasmer_config_t,
+    metering: &wasmer_metering_config_t,
+) {
+    config.metering = Some(*metering);
+}
+
+/// Deletes a config created by [`wasmer_config_new`].
+#[no_mangle]
+pub extern "C" fn wasmer_config_delete(_config: Option<Box<wasmer_config_t>>) {}
+
+/// Non-standard Wasmer-specific API to create an engine picking the
+/// compiler backend named in `config`, instead of the fixed default
+/// compiler `wasm_engine_new` assumes. Returns `NULL` if this build
+/// of the library was compiled without the requested backend's
+/// Cargo feature enabled.
+///
+/// # Example
+///
+/// ```rust
+/// # use inline_c::assert_c;
+/// # fn main() {
+/// #    (assert_c! {
+/// # #include "tests/wasmer_wasm.h"
+/// #
+/// int main() {
+///     wasmer_config_t* config = wasmer_config_new();
+///     wasmer_config_set_compiler(config, CRANELIFT);
+///
+///     wasm_engine_t* engine = wasm_engine_new_with_config(config);
+///     assert(engine != NULL);
+///
+///     // The engine works like any other: it can build a store and
+///     // compile a module with it.
+///     wasm_store_t* store = wasm_store_new(engine);
+///
+///     wasm_byte_vec_t wat;
+///     wasmer_byte_vec_new_from_string(&wat, "(module)");
+///     wasm_byte_vec_t wasm;
+///     wat2wasm(&wat, &wasm);
+///
+///     wasm_module_t* module = wasm_module_new(store, &wasm);
+///     assert(module != NULL);
+///
+///     wasm_module_delete(module);
+///     wasm_byte_vec_delete(&wasm);
+///     wasm_byte_vec_delete(&wat);
+///     wasm_store_delete(store);
+///     wasm_engine_delete(engine);
+///
+///     return 0;
+/// }
+/// #    })
+/// #    .success();
+/// # }
+/// ```
+#[no_mangle]
+pub extern "C" fn wasm_engine_new_with_config(
+    config: Box<wasmer_config_t>,
+) -> Option<Box<super::engine::wasm_engine_t>> {
+    let mut compiler_config: Box<dyn CompilerConfig> = match config.compiler {
+        #[cfg(feature = "cranelift")]
+        wasmer_compiler_t::CRANELIFT => Box::new(wasmer_compiler_cranelift::Cranelift::default()),
+        #[cfg(not(feature = "cranelift"))]
+        wasmer_compiler_t::CRANELIFT => return None,
+
+        #[cfg(feature = "llvm")]
+        wasmer_compiler_t::LLVM => Box::new(wasmer_compiler_llvm::LLVM::default()),
+        #[cfg(not(feature = "llvm"))]
+        wasmer_compiler_t::LLVM => return None,
+
+        #[cfg(feature = "singlepass")]
+        wasmer_compiler_t::SINGLEPASS => {
+            Box::new(wasmer_compiler_singlepass::Singlepass::default())
+        }
+        #[cfg(not(feature = "singlepass"))]
+        wasmer_compiler_t::SINGLEPASS => return None,
+    };
+
+    if let Some(metering_config) = config.metering {
+        let cost_function = metering_config.cost_function;
+
+        compiler_config.push_middleware(Arc::new(wasmer_middlewares::Metering::new(
+            metering_config.initial_limit,
+            move |operator: &Operator| -> u64 { cost_function(operator_kind(operator)) },
+        )));
+
+        compiler_config.push_middleware(Arc::new(StackLimiter {
+            max_height: metering_config.max_stack_height,
+            signatures: Mutex::new(Default::default()),
+            function_signatures: Mutex::new(Default::default()),
+        }));
+    }
+
+    let engine = wasmer::Universal::new(compiler_config).engine();
+
+    Some(Box::new(super::engine::wasm_engine_t { inner: engine }))
+}
+
+/// Non-standard Wasmer-specific API to read how many metering
+/// points an instance of a module compiled with
+/// [`wasmer_config_set_metering`] has left before it traps. Returns
+/// `0` and sets `*exhausted` to `true` if the instance already ran
+/// out of points.
+///
+/// # Example
+///
+/// ```rust
+/// # use inline_c::assert_c;
+/// # fn main() {
+/// #    (assert_c! {
+/// # #include "tests/wasmer_wasm.h"
+/// #
+/// uint64_t cost_of(wasmer_operator_kind_t kind) {
+///     return 1;
+/// }
+///
+/// int main() {
+///     wasmer_config_t* config = wasmer_config_new();
+///     wasmer_config_set_compiler(config, CRANELIFT);
+///
+///     wasmer_metering_config_t metering = {
+///         .cost_function = cost_of,
+///         .initial_limit = 100,
+///         .max_stack_height = 256,
+///     };
+///     wasmer_config_set_metering(config, &metering);
+///
+///     wasm_engine_t* engine = wasm_engine_new_with_config(config);
+///     assert(engine != NULL);
+///     wasm_store_t* store = wasm_store_new(engine);
+///
+///     wasm_byte_vec_t wat;
+///     wasmer_byte_vec_new_from_string(
+///         &wat,
+///         "(module (func (export \"run\") (result i32) (i32.const 42)))"
+///     );
+///     wasm_byte_vec_t wasm;
+///     wat2wasm(&wat, &wasm);
+///
+///     wasm_module_t* module = wasm_module_new(store, &wasm);
+///     assert(module != NULL);
+///
+///     wasm_extern_vec_t imports = WASM_EMPTY_VEC;
+///     wasm_trap_t* trap = NULL;
+///     wasm_instance_t* instance = wasm_instance_new(store, module, &imports, &trap);
+///     assert(instance != NULL);
+///
+///     bool exhausted = true;
+///     uint64_t remaining = wasmer_instance_get_remaining_points(instance, &exhausted);
+///     assert(!exhausted);
+///     assert(remaining <= 100);
+///
+///     wasm_extern_vec_t exports;
+///     wasm_instance_exports(instance, &exports);
+///     // `run`, plus the two globals the metering middleware exports
+///     // to track the remaining points and exhaustion.
+///     assert(exports.size == 3);
+///     wasm_func_t* run = wasm_extern_as_func(exports.data[0]);
+///     assert(run != NULL);
+///
+///     wasm_val_vec_t args = WASM_EMPTY_VEC;
+///     wasm_val_t result_val[1] = { WASM_I32_VAL(0) };
+///     wasm_val_vec_t results = WASM_ARRAY_VEC(result_val);
+///     wasm_trap_t* call_trap = wasm_func_call(run, &args, &results);
+///     assert(call_trap == NULL);
+///     assert(result_val[0].of.i32 == 42);
+///
+///     // Each operator the call executed was charged for, so fewer
+///     // points remain than before the call.
+///     uint64_t after_call = wasmer_instance_get_remaining_points(instance, &exhausted);
+///     assert(!exhausted);
+///     assert(after_call < remaining);
+///
+///     wasm_extern_vec_delete(&exports);
+///
+///     wasmer_instance_set_remaining_points(instance, 50);
+///     remaining = wasmer_instance_get_remaining_points(instance, &exhausted);
+///     assert(!exhausted);
+///     assert(remaining == 50);
+///
+///     wasm_instance_delete(instance);
+///     wasm_module_delete(module);
+///     wasm_byte_vec_delete(&wasm);
+///     wasm_byte_vec_delete(&wat);
+///     wasm_store_delete(store);
+///     wasm_engine_delete(engine);
+///
+///     return 0;
+/// }
+/// #    })
+/// #    .success();
+/// # }
+/// ```
+#[no_mangle]
+pub unsafe extern "C" fn wasmer_instance_get_remaining_points(
+    instance: &wasm_instance_t,
+    exhausted: &mut bool,
+) -> u64 {
+    match metering::get_remaining_points(&instance.inner) {
+        MeteringPoints::Remaining(points) => {
+            *exhausted = false;
+
+            points
+        }
+        MeteringPoints::Exhausted => {
+            *exhausted = true;
+
+            0
+        }
+    }
+}
+
+/// Non-standard Wasmer-specific API to set how many metering points
+/// remain for an instance of a module compiled with
+/// [`wasmer_config_set_metering`], e.g. to top an instance back up
+/// before reusing it.
+#[no_mangle]
+pub unsafe extern "C" fn wasmer_instance_set_remaining_points(
+    instance: &mut wasm_instance_t,
+    points: u64,
+) {
+    metering::set_remaining_points(&mut instance.inner, points);
+}